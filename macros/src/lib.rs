@@ -3,6 +3,7 @@
 use proc_macro2::Span;
 use quote::quote;
 use syn::{
+    ext::IdentExt,
     parse::{Parse, ParseStream},
     parse_macro_input,
     punctuated::Punctuated,
@@ -44,9 +45,62 @@ impl Parse for ProtoFn {
 }
 
 ///
-/// Delcares a new method in our API.
+/// `extern event name(Payload)` &mdash; declares a server-initiated event
+/// instead of a callable method; see [ProtoFn] for the method form.
 ///
-/// In the parenthesis, only put the parameter's types (no identifiers).
+struct ProtoEvent {
+    attrs: Vec<syn::Attribute>,
+    _externality: syn::Token![extern],
+    _event: syn::Ident,
+    ident: syn::Ident,
+    _paren: syn::token::Paren,
+    payload: syn::Type,
+}
+
+impl Parse for ProtoEvent {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let inner;
+
+        Ok(Self {
+            attrs: input.call(Attribute::parse_outer)?,
+            _externality: input.parse()?,
+            _event: input.parse()?,
+            ident: input.parse()?,
+            _paren: syn::parenthesized!(inner in input),
+            payload: inner.parse()?,
+        })
+    }
+}
+
+///
+/// Either form `declare!` accepts.
+///
+enum ProtoItem {
+    Fn(ProtoFn),
+    Event(ProtoEvent),
+}
+
+impl Parse for ProtoItem {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // Peek past any attributes and the `extern` keyword to tell the two
+        // forms apart: `extern fn ...` vs `extern event ...`.
+        let ahead = input.fork();
+        let _ = ahead.call(Attribute::parse_outer)?;
+        let _: syn::Token![extern] = ahead.parse()?;
+        let keyword = syn::Ident::parse_any(&ahead)?;
+
+        if keyword == "event" {
+            input.parse().map(ProtoItem::Event)
+        } else {
+            input.parse().map(ProtoItem::Fn)
+        }
+    }
+}
+
+///
+/// Delcares a new method or event in our API.
+///
+/// In the parenthesis of a method, only put the parameter's types (no identifiers).
 ///
 /// Syntax:
 /// ```ignore
@@ -65,14 +119,41 @@ impl Parse for ProtoFn {
 ///     ///
 ///     extern fn even_cooler(usize) -> String
 /// );
+///
+/// declare!(
+///     ///
+///     /// Pushed by the server while `even_cooler` is still running.
+///     ///
+///     extern event progress(u8)
+/// );
 /// ```
 ///
-/// Note: all inputs and outputs **must** implement `serde::Serialize`, `serde::DeserializeOwned`.
+/// Note: all inputs, outputs and event payloads **must** implement
+/// `serde::Serialize`, `serde::DeserializeOwned`.
 ///
 #[proc_macro]
-pub fn declare(func: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let sig: ProtoFn = parse_macro_input!(func);
+pub fn declare(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    match parse_macro_input!(item as ProtoItem) {
+        ProtoItem::Fn(sig) => expand_fn(sig),
+        ProtoItem::Event(sig) => expand_event(sig),
+    }
+}
+
+fn expand_event(sig: ProtoEvent) -> proc_macro::TokenStream {
+    let ident = sig.ident;
+    let ident_str = syn::LitStr::new(ident.to_string().as_str(), ident.span());
+    let attrs = sig.attrs;
+    let payload = sig.payload;
+
+    quote! {
+        #(#attrs)*
+        #[allow(non_upper_case_globals)]
+        pub const #ident: ::avsocket::Event<#payload> = ::avsocket::eventify(#ident_str);
+    }
+    .into()
+}
 
+fn expand_fn(sig: ProtoFn) -> proc_macro::TokenStream {
     // Make type for the Method<(...,), ...>
 
     let input_ty = sig.inputs.iter().cloned();
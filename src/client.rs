@@ -21,8 +21,10 @@
 //! async fn main() -> anyhow::Result<()> {
 //!     let path = /* ... */;
 //!
-//!     let mut dispatcher = Dispatcher::connect(&path).await?;    
+//!     let dispatcher = Dispatcher::connect(&path).await?;
 //!
+//!     // `dispatcher` is cheaply cloneable, so many calls can be in flight
+//!     // at once over the same connection.
 //!     let response = dispatcher.dispatch(proto::add(5,23)).await;
 //!     println!("{response:?}");
 //!
@@ -32,46 +34,160 @@
 //!
 //!
 
-use std::{marker::PhantomData, path::Path};
-
-use crate::transport::{Request, Response};
-use futures::{SinkExt, StreamExt};
+use std::{
+    collections::{BTreeSet, HashMap},
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
-use serde::de::DeserializeOwned;
-use tokio::net::UnixStream;
-use tokio_util::{
-    bytes::Bytes,
-    codec::{self, Framed, LengthDelimitedCodec},
+use crate::{
+    transport::{
+        self,
+        handshake::{self, CAP_COMPRESSION, CAP_ENCRYPTION, CAP_MSGPACK},
+        layer::{CompressionLayer, EncryptionLayer, LayerStack},
+        scheduler::{ChunkReader, ChunkWriter},
+        Request, WireFormat,
+    },
+    Event,
 };
+use futures::{stream::SplitStream, StreamExt};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{net::UnixStream, sync::oneshot};
+use tokio_util::codec::{self, Framed};
+
+///
+/// Capabilities this client can make use of if the server also advertises
+/// them; negotiated down to the intersection by [handshake::negotiate].
+///
+const OFFERED_CAPABILITIES: [&str; 3] = [CAP_COMPRESSION, CAP_ENCRYPTION, CAP_MSGPACK];
 
-pub struct Dispatcher(Framed<UnixStream, LengthDelimitedCodec>);
+///
+/// Replies awaiting pickup by the [Dispatcher::dispatch] call that sent the
+/// request, keyed by the request's id.
+///
+type Pending = Arc<Mutex<HashMap<String, oneshot::Sender<transport::Response<Vec<u8>>>>>>;
+
+///
+/// Handlers registered with [Dispatcher::on], keyed by event name.
+///
+type Events = Arc<Mutex<HashMap<&'static str, Box<dyn Fn(transport::EventFrame) + Send + Sync>>>>;
+
+///
+/// Dispatches [Request]s to a server and awaits their [transport::Response],
+/// and routes server-initiated [Event]s to handlers registered with
+/// [Dispatcher::on].
+///
+/// Cheaply cloneable: every clone shares the same background read task and
+/// connection, so many calls can be in flight at once from different tasks.
+///
+#[derive(Clone)]
+pub struct Dispatcher {
+    writer: ChunkWriter,
+    layers: Arc<LayerStack>,
+    format: WireFormat,
+    pending: Pending,
+    events: Events,
+    capabilities: BTreeSet<String>,
+}
 
 impl Dispatcher {
     pub async fn connect<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let con = tokio::net::UnixStream::connect(path).await?;
 
-        let transport = codec::Framed::new(con, codec::LengthDelimitedCodec::new());
-        Ok(Self(transport))
+        let mut transport = codec::Framed::new(con, codec::LengthDelimitedCodec::new());
+        let session =
+            handshake::negotiate(&mut transport, OFFERED_CAPABILITIES.map(str::to_string))
+                .await?;
+
+        let mut layers = LayerStack::new();
+        if session.capabilities.contains(CAP_COMPRESSION) {
+            layers.push(Box::new(CompressionLayer::default()));
+        }
+        if session.capabilities.contains(CAP_ENCRYPTION) {
+            layers.push(Box::new(EncryptionLayer::new(session.shared_secret)));
+        }
+        let layers = Arc::new(layers);
+
+        let format = if session.capabilities.contains(CAP_MSGPACK) {
+            WireFormat::MessagePack
+        } else {
+            WireFormat::Bincode
+        };
+
+        let (sink, stream) = transport.split();
+        let writer = ChunkWriter::new(sink);
+        let pending: Pending = Arc::default();
+        let events: Events = Arc::default();
+
+        tokio::spawn(Self::read_loop(
+            stream,
+            Arc::clone(&layers),
+            format,
+            Arc::clone(&pending),
+            Arc::clone(&events),
+        ));
+
+        Ok(Self {
+            writer,
+            layers,
+            format,
+            pending,
+            events,
+            capabilities: session.capabilities,
+        })
+    }
+
+    ///
+    /// Capabilities negotiated with the server during the handshake
+    /// performed in [Dispatcher::connect].
+    ///
+    pub fn capabilities(&self) -> &BTreeSet<String> {
+        &self.capabilities
+    }
+
+    ///
+    /// Registers `handler` to run whenever the server pushes `event`,
+    /// replacing any handler previously registered for the same event.
+    /// Pushes whose body fails to decode as `T` are silently dropped.
+    ///
+    pub fn on<T>(&self, event: Event<T>, handler: impl Fn(T) + Send + Sync + 'static)
+    where
+        T: Serialize + DeserializeOwned + 'static,
+    {
+        self.events.lock().unwrap().insert(
+            event.name(),
+            Box::new(move |frame: transport::EventFrame| {
+                if let Ok(payload) = bincode::deserialize::<T>(frame.body()) {
+                    handler(payload);
+                }
+            }),
+        );
     }
 
     pub async fn dispatch<R: DeserializeOwned>(
-        &mut self,
+        &self,
         req: (Request<Vec<u8>>, PhantomData<R>),
     ) -> anyhow::Result<R> {
-        let transport = &mut self.0;
-        let bin = Bytes::from_iter(bincode::serialize(&req)?);
-        transport.send(bin).await?;
+        let (req, _) = req;
+        let id = req.id().to_string();
+        let priority = req.priority();
 
-        let bin = transport.next().await.unwrap()?;
-        let res = Response::from_bytes(bin)
-            .map_or_else(
-                || {
-                    Err(anyhow::format_err!(
-                        "Could not deserialize response from binary!"
-                    ))
-                },
-                Ok,
-            )?
+        let bytes = self.format.serialize(&req)?;
+        let bytes = self.layers.wrap_outgoing(bytes.into()).to_vec();
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), tx);
+
+        if let Err(e) = self.writer.enqueue(id.clone(), priority, bytes) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        let res = rx
+            .await
+            .map_err(|_| anyhow::format_err!("connection closed before a reply arrived"))?
             .convert_inner::<R>()
             .map_or_else(
                 || {
@@ -82,6 +198,210 @@ impl Dispatcher {
                 Ok,
             )?;
 
-        Ok(res.consume())
+        res.into_result().map_err(Into::into)
+    }
+
+    ///
+    /// Reads reassembled [transport::Envelope]s off the connection for as
+    /// long as it stays open. A [transport::Envelope::Reply] is routed to
+    /// the [Dispatcher::dispatch] call waiting on its id (dropped if none is
+    /// waiting); a [transport::Envelope::Event] is routed to whatever
+    /// handler is registered for it via [Dispatcher::on] (dropped if none
+    /// is registered).
+    ///
+    async fn read_loop(
+        mut stream: SplitStream<Framed<UnixStream, codec::LengthDelimitedCodec>>,
+        layers: Arc<LayerStack>,
+        format: WireFormat,
+        pending: Pending,
+        events: Events,
+    ) {
+        let mut reader = ChunkReader::new();
+
+        while let Some(Ok(frame)) = stream.next().await {
+            let Some((_priority, bytes)) = reader.feed(frame) else {
+                continue;
+            };
+
+            let Ok(bytes) = layers.unwrap_incoming(bytes.into()) else {
+                continue;
+            };
+
+            let Some(envelope) = transport::Envelope::from_bytes(bytes, &format) else {
+                continue;
+            };
+
+            match envelope {
+                transport::Envelope::Reply(response) => {
+                    if let Some(tx) = pending.lock().unwrap().remove(response.to()) {
+                        let _ = tx.send(response);
+                    }
+                }
+                transport::Envelope::Event(frame) => {
+                    if let Some(handler) = events.lock().unwrap().get(frame.method()) {
+                        handler(frame);
+                    }
+                }
+            }
+        }
+
+        // The stream ended (or yielded an error tokio_util surfaces as
+        // `None`). Nobody else will ever send on a `tx` still sitting in
+        // `pending`, so drop them all here: each corresponding `dispatch()`
+        // call is `.await`ing its `rx` and will turn the resulting
+        // `RecvError` into a "connection closed" `Err` instead of hanging
+        // forever.
+        pending.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paired_stream() -> (UnixStream, SplitStream<Framed<UnixStream, codec::LengthDelimitedCodec>>) {
+        let (a, b) = UnixStream::pair().expect("paired sockets");
+        let framed = codec::Framed::new(a, codec::LengthDelimitedCodec::new());
+        let (_sink, stream) = framed.split();
+        (b, stream)
+    }
+
+    #[tokio::test]
+    async fn pending_replies_are_dropped_when_the_connection_closes() {
+        let (peer, stream) = paired_stream();
+
+        let pending: Pending = Arc::default();
+        let events: Events = Arc::default();
+
+        let (tx, rx) = oneshot::channel();
+        pending.lock().unwrap().insert("in-flight".to_string(), tx);
+
+        let read_loop = tokio::spawn(Dispatcher::read_loop(
+            stream,
+            Arc::new(LayerStack::new()),
+            WireFormat::Bincode,
+            Arc::clone(&pending),
+            events,
+        ));
+
+        // Closing the peer's end makes `stream.next()` yield `None`.
+        drop(peer);
+        read_loop.await.expect("read_loop task panicked");
+
+        assert!(pending.lock().unwrap().is_empty());
+        assert!(rx.await.is_err(), "dispatch() should see an Err, not hang");
+    }
+
+    #[tokio::test]
+    async fn concurrent_replies_route_to_the_waiter_with_a_matching_id() {
+        let (peer, stream) = paired_stream();
+
+        let pending: Pending = Arc::default();
+        let events: Events = Arc::default();
+
+        let req_1 = Request::new("m", Vec::<u8>::new());
+        let req_2 = Request::new("m", Vec::<u8>::new());
+        let (id_1, id_2) = (req_1.id().to_string(), req_2.id().to_string());
+
+        let (tx_1, rx_1) = oneshot::channel();
+        let (tx_2, rx_2) = oneshot::channel();
+        pending.lock().unwrap().insert(id_1.clone(), tx_1);
+        pending.lock().unwrap().insert(id_2.clone(), tx_2);
+
+        tokio::spawn(Dispatcher::read_loop(
+            stream,
+            Arc::new(LayerStack::new()),
+            WireFormat::Bincode,
+            Arc::clone(&pending),
+            events,
+        ));
+
+        let writer = ChunkWriter::new(codec::Framed::new(peer, codec::LengthDelimitedCodec::new()));
+        // Reply to the second call first, to show routing doesn't depend on
+        // the order calls were made in.
+        for (req, body) in [(req_2, vec![9u8]), (req_1, vec![1u8, 2, 3])] {
+            let envelope = transport::Envelope::Reply(req.reply(body));
+            let bytes = envelope.to_bytes(&WireFormat::Bincode);
+            writer
+                .enqueue(uuid::Uuid::new_v4().to_string(), transport::PRIO_NORMAL, bytes)
+                .unwrap();
+        }
+
+        let res_1 = rx_1.await.expect("reply for req_1");
+        let res_2 = rx_2.await.expect("reply for req_2");
+
+        assert_eq!(res_1.to(), id_1);
+        assert_eq!(res_2.to(), id_2);
+    }
+
+    ///
+    /// Regression test for a bug where `dispatch()` decoded the inner
+    /// `Params`/`Returns` body with the connection's negotiated outer
+    /// [WireFormat] instead of bincode, breaking every ordinary call once a
+    /// connection negotiated anything other than [WireFormat::Bincode].
+    /// Drives a full call through [Dispatcher::dispatch] against a
+    /// hand-rolled peer that replies the same way `Handler::add` does, with
+    /// the outer frame encoded as [WireFormat::MessagePack].
+    ///
+    #[tokio::test]
+    async fn dispatch_round_trips_an_ordinary_call_through_a_non_default_outer_format() {
+        let (a, b) = UnixStream::pair().expect("paired sockets");
+        let (sink_a, stream_a) = codec::Framed::new(a, codec::LengthDelimitedCodec::new()).split();
+        let (sink_b, mut stream_b) =
+            codec::Framed::new(b, codec::LengthDelimitedCodec::new()).split();
+
+        let format = WireFormat::MessagePack;
+        let pending: Pending = Arc::default();
+        let events: Events = Arc::default();
+
+        let dispatcher = Dispatcher {
+            writer: ChunkWriter::new(sink_a),
+            layers: Arc::new(LayerStack::new()),
+            format,
+            pending: Arc::clone(&pending),
+            events: Arc::clone(&events),
+            capabilities: BTreeSet::new(),
+        };
+
+        tokio::spawn(Dispatcher::read_loop(
+            stream_a,
+            Arc::new(LayerStack::new()),
+            format,
+            pending,
+            events,
+        ));
+
+        // Stand-in for the server side of `Server::run`: decode the outer
+        // frame with the negotiated format, the inner params with plain
+        // bincode (as `Handler::add` does), and mirror a reply back the
+        // same way.
+        let server = tokio::spawn(async move {
+            let mut reader = ChunkReader::new();
+            let bytes = loop {
+                let frame = stream_b.next().await.unwrap().unwrap();
+                if let Some((_, bytes)) = reader.feed(frame) {
+                    break bytes;
+                }
+            };
+
+            let req = transport::Request::<Vec<u8>>::from_bytes(bytes, &format)
+                .expect("outer frame round-trips through MessagePack");
+            let (a, b): (usize, usize) = bincode::deserialize(req.body()).unwrap();
+            let res = req.reply(bincode::serialize(&(a + b)).unwrap());
+
+            let out = transport::Envelope::Reply(res).to_bytes(&format);
+            ChunkWriter::new(sink_b)
+                .enqueue(uuid::Uuid::new_v4().to_string(), transport::PRIO_NORMAL, out)
+                .unwrap();
+        });
+
+        let req = Request::new("add", bincode::serialize(&(2usize, 3usize)).unwrap());
+        let sum: usize = dispatcher
+            .dispatch((req, PhantomData))
+            .await
+            .expect("call should succeed through a non-default outer format");
+        assert_eq!(sum, 5);
+
+        server.await.unwrap();
     }
 }
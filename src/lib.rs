@@ -80,195 +80,37 @@ where
 pub use macros::declare;
 
 ///
-/// Utilities and middleware to help transport data.
+/// Stores metadata about a server-initiated event &mdash; its
+/// * Name; and
+/// * Payload type.
 ///
-/// * Makes use of `serde` and `bincode` to represent all the data as binary.
-/// * Governs the structure of communication &mdash; [Request]s from the client,
-/// followed by [Response]s from the server.
-///
-pub mod transport {
-    use serde::{de::DeserializeOwned, Deserialize, Serialize};
-
-    ///
-    /// Client-to-server message.
-    ///
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Request<Body> {
-        ///
-        /// Unique UUID v4 for this request, to keep track of the server's response.
-        ///
-        id: String,
-
-        ///
-        /// Method's  ID.
-        ///
-        method: String,
-
-        ///
-        /// Payload.
-        ///
-        body: Body,
-    }
-
-    impl<Body> Clone for Request<Body>
-    where
-        Body: Clone,
-    {
-        fn clone(&self) -> Self {
-            Self {
-                id: self.id.clone(),
-                method: self.method.clone(),
-                body: self.body.clone(),
-            }
-        }
-    }
-
-    impl<Body> Request<Body> {
-        pub fn new(label: impl ToString, body: Body) -> Self {
-            Self {
-                id: uuid::Uuid::new_v4().to_string(),
-                method: label.to_string(),
-                body,
-            }
-        }
-
-        ///
-        /// Serialize this [Request] as bytes using `bincode`
-        /// (guaranteed not to fail... well *nearly*...).
-        ///
-        pub fn to_bytes(self) -> Vec<u8>
-        where
-            Body: Serialize,
-        {
-            let Self { id, method, body } = self;
-            let tmp = Request {
-                id,
-                method,
-                body: bincode::serialize(&body).expect("Valid serialize"),
-            };
-            bincode::serialize(&tmp).expect("Valid serialize Round 2")
-        }
-
-        ///
-        /// Make a reply to this [Request] with the given body.
-        ///
-        pub fn reply<NewBody>(&self, body: NewBody) -> Response<NewBody> {
-            Response {
-                to: self.id.clone(),
-                method: self.method.clone(),
-                body,
-            }
-        }
-
-        pub fn id(&self) -> &str {
-            &self.id
-        }
-
-        pub fn body(&self) -> &Body {
-            &self.body
-        }
-
-        pub fn method(&self) -> &str {
-            &self.method
-        }
-    }
-
-    impl Request<Vec<u8>> {
-        ///
-        /// Deserialize a raw request, with a type-erased body.
-        ///
-        /// This is done before deserializing the body seperately
-        /// (for generic erasure reasons).
-        ///
-        pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Option<Self> {
-            bincode::deserialize(bytes.as_ref()).ok()
-        }
-
-        ///
-        /// Deserialize this [Request]'s inner body to the desired type.
-        /// 
-        pub fn convert_inner<Body: DeserializeOwned>(self) -> Option<Request<Body>> {
-            let Self { id, method, body } = self;
-
-            bincode::deserialize(&body)
-                .map(|body| Request { id, method, body })
-                .ok()
-        }
-    }
-
-    impl Response<Vec<u8>> {
-        ///
-        /// Deserialize a raw [Response] into its type-erased form. 
-        /// 
-        pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Option<Self> {
-            bincode::deserialize(bytes.as_ref()).ok()
-        }
-
-        ///
-        /// Deserialize the inner type-erased body to a type.
-        /// 
-        pub fn convert_inner<Body: DeserializeOwned>(self) -> Option<Response<Body>> {
-            let Self { to, method, body } = self;
-
-            bincode::deserialize(&body)
-                .map(|body| Response { to, method, body })
-                .ok()
-        }
-    }
-
-    ///
-    /// Server-to-client message.
-    /// 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Response<Body> {
-        ///
-        /// Same as the associated [Request]'s id field
-        ///
-        to: String,
-
-        ///
-        /// Method's  ID.
-        ///
-        method: String,
-
-        ///
-        /// Payload.
-        ///
-        body: Body,
-    }
-
-    impl<Body> Response<Body> {
-        pub fn body(&self) -> &Body {
-            &self.body
-        }
-
-        pub fn consume(self) -> Body {
-            self.body
-        }
-
-        pub fn to(&self) -> &str {
-            &self.to
-        }
+#[derive(Debug, Clone, Copy)]
+pub struct Event<Body>(&'static str, PhantomData<Body>)
+where
+    Body: Serialize + DeserializeOwned;
 
+///
+/// Converts a name into an [Event] descriptor.
+///
+#[allow(dead_code)]
+pub const fn eventify<Body>(name: &'static str) -> Event<Body>
+where
+    Body: Serialize + DeserializeOwned,
+{
+    Event(name, PhantomData::<Body>)
+}
 
-        ///
-        /// Serialize this [Response] as bytes.
-        /// 
-        pub fn to_bytes(self) -> Vec<u8>
-        where
-            Body: Serialize,
-        {
-            let Self { to, method, body } = self;
-            let tmp = Response {
-                to,
-                method,
-                body: bincode::serialize(&body).expect("Valid serialize"),
-            };
-            bincode::serialize(&tmp).expect("Valid serialize Round 2")
-        }
+impl<Body> Event<Body>
+where
+    Body: Serialize + DeserializeOwned,
+{
+    pub fn name(&self) -> &'static str {
+        self.0
     }
 }
 
+pub mod transport;
+
 #[cfg(test)]
 pub mod transport_tests {
     use super::{methodify, Method};
@@ -21,7 +21,7 @@
 //! ```ignore
 //!
 //! mod proto;
-//! 
+//!
 //! use avsocket::server::{Handler, Server};
 //!
 //! #[tokio::main]
@@ -31,29 +31,224 @@
 //!
 //!     handler
 //!         .add(proto::add, &|a, b| a + b)
-//!         .add(proto::sub, &|a, b| a - b);
+//!         .add(proto::sub, &|a, b| a - b)
+//!         .add_async(proto::fetch, &|key| async move { db::lookup(key).await });
 //!
 //!     Server::run(&path, handler).await
 //! }
 //! ```
+//! ---
+//! Calling [emit] from inside a handler pushes a server-initiated [Event] to
+//! the client that sent the in-flight request, correlated to it so the
+//! client can tell which call it belongs to:
+//! ```ignore
+//! declare!(extern event progress(u8));
+//!
+//! handler.add_async(proto::long_task, &|| async move {
+//!     let _ = avsocket::server::emit(proto::progress, &50u8);
+//!     do_the_work().await
+//! });
+//! ```
+//! ---
+//! [capabilities] works the same way, returning what was negotiated for the
+//! connection the in-flight request arrived on:
+//! ```ignore
+//! handler.add(proto::transfer, &|key| {
+//!     if avsocket::server::capabilities().unwrap_or_default().contains("compression") {
+//!         /* ... */
+//!     }
+//!     db::lookup(key)
+//! });
+//! ```
+//! ---
+//! [emit]/[capabilities] only work from inside an in-flight request, since
+//! they're tied to the call's correlation id. A genuinely unprompted push
+//! &mdash; a notification not triggered by any client call &mdash; goes through
+//! [sessions] instead, which lists every client currently connected:
+//! ```ignore
+//! declare!(extern event announcement(String));
+//!
+//! tokio::spawn(async move {
+//!     loop {
+//!         tokio::time::sleep(Duration::from_secs(60)).await;
+//!         for session in avsocket::server::sessions() {
+//!             let _ = session.push(proto::announcement, &"...".to_string(), PRIO_NORMAL);
+//!         }
+//!     }
+//! });
+//! ```
 //!
 
-use std::{collections::HashMap, fmt::Debug, fs, marker::Tuple, path::Path, sync::OnceLock};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+    fs,
+    future::Future,
+    marker::Tuple,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+};
 
-use futures::{SinkExt, StreamExt};
+use futures::StreamExt;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::net::UnixListener;
-use tokio_util::{
-    bytes::Bytes,
-    codec::{Framed, LengthDelimitedCodec},
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::{
+    transport::{
+        self,
+        handshake::{self, CAP_COMPRESSION, CAP_ENCRYPTION, CAP_MSGPACK},
+        layer::{CompressionLayer, EncryptionLayer, LayerStack},
+        scheduler::{ChunkReader, ChunkWriter},
+        RequestPriority, RpcError, WireFormat,
+    },
+    Event, Method,
 };
 
-use crate::{transport, Method};
+///
+/// Capabilities this server can make use of if the client also advertises
+/// them; negotiated down to the intersection by [handshake::negotiate].
+///
+const OFFERED_CAPABILITIES: [&str; 3] = [CAP_COMPRESSION, CAP_ENCRYPTION, CAP_MSGPACK];
+
+///
+/// Handle for pushing [Event]s to a connected client, independent of any
+/// particular in-flight request. Obtained from [sessions]; unlike [emit],
+/// not tied to a request's correlation id, so it can push a notification
+/// nobody asked for (`EventFrame::ref_id` is `None`). Also backs [Emitter],
+/// which adds the per-request correlation [emit] needs.
+///
+#[derive(Clone)]
+pub struct Session {
+    writer: ChunkWriter,
+    layers: Arc<LayerStack>,
+    format: WireFormat,
+    capabilities: BTreeSet<String>,
+}
+
+impl Session {
+    ///
+    /// Push `event` with `payload` to this client at `priority`, with no
+    /// correlation to any particular request.
+    ///
+    pub fn push<Body: Serialize>(
+        &self,
+        event: Event<Body>,
+        payload: &Body,
+        priority: RequestPriority,
+    ) -> anyhow::Result<()> {
+        let body = bincode::serialize(payload)?;
+        let frame = transport::EventFrame::new(event.name(), priority, None, body);
+        let bytes = transport::Envelope::Event(frame).to_bytes(&self.format);
+        let bytes = self.layers.wrap_outgoing(bytes.into()).to_vec();
+
+        self.writer
+            .enqueue(uuid::Uuid::new_v4().to_string(), priority, bytes)
+    }
+
+    ///
+    /// Capabilities negotiated with this client during the handshake.
+    ///
+    pub fn capabilities(&self) -> &BTreeSet<String> {
+        &self.capabilities
+    }
+}
+
+///
+/// Every client currently connected, keyed by connection id; populated and
+/// drained automatically by `Server::run` as connections come and go.
+///
+static SESSIONS: OnceLock<std::sync::Mutex<HashMap<String, Session>>> = OnceLock::new();
+
+fn session_registry() -> &'static std::sync::Mutex<HashMap<String, Session>> {
+    SESSIONS.get_or_init(Default::default)
+}
+
+///
+/// Every client currently connected, for pushing [Event]s that aren't tied
+/// to any particular in-flight request (e.g. a background task notifying
+/// every connected client of some shared state change). Unlike [emit],
+/// callable from anywhere &mdash; not just from inside a [Handler::add]/
+/// [Handler::add_async] callback.
+///
+pub fn sessions() -> Vec<Session> {
+    session_registry().lock().unwrap().values().cloned().collect()
+}
+
+tokio::task_local! {
+    static CURRENT_EMITTER: Emitter;
+}
+
+///
+/// Handle for pushing [Event]s to the client currently being served,
+/// correlated to the in-flight request by its id. Obtained implicitly by
+/// calling [emit] from inside a callback registered with [Handler::add] or
+/// [Handler::add_async]; `Server::run` scopes one to each request's future.
+///
+#[derive(Clone)]
+struct Emitter {
+    ref_id: String,
+    priority: RequestPriority,
+    session: Session,
+}
+
+impl Emitter {
+    fn push<Body: Serialize>(&self, event: Event<Body>, payload: &Body) -> anyhow::Result<()> {
+        let body = bincode::serialize(payload)?;
+        let frame = transport::EventFrame::new(
+            event.name(),
+            self.priority,
+            Some(self.ref_id.clone()),
+            body,
+        );
+        let bytes = transport::Envelope::Event(frame).to_bytes(&self.session.format);
+        let bytes = self.session.layers.wrap_outgoing(bytes.into()).to_vec();
+
+        self.session
+            .writer
+            .enqueue(uuid::Uuid::new_v4().to_string(), self.priority, bytes)
+    }
+}
+
+///
+/// Push `event` with `payload` to the client that sent the request
+/// currently being handled, correlated to it so the client can match the
+/// push up with the call that triggered it.
+///
+/// Must be called from inside a callback registered with [Handler::add] or
+/// [Handler::add_async]; returns an error otherwise. For a push that isn't
+/// tied to any in-flight request, use [sessions] and [Session::push].
+///
+pub fn emit<Body: Serialize>(event: Event<Body>, payload: &Body) -> anyhow::Result<()> {
+    CURRENT_EMITTER
+        .try_with(|emitter| emitter.push(event, payload))
+        .map_err(|_| anyhow::format_err!("emit() called outside of a request handler"))?
+}
+
+///
+/// Capabilities negotiated during the handshake for the connection whose
+/// request is currently being handled, e.g. to decide whether it's worth
+/// [emit]ting a progress event the client may not have asked for.
+///
+/// Must be called from inside a callback registered with [Handler::add] or
+/// [Handler::add_async]; returns an error otherwise.
+///
+pub fn capabilities() -> anyhow::Result<BTreeSet<String>> {
+    CURRENT_EMITTER
+        .try_with(|emitter| emitter.session.capabilities.clone())
+        .map_err(|_| anyhow::format_err!("capabilities() called outside of a request handler"))
+}
+
+///
+/// A boxed, already-in-flight reply future, as returned by a [RawCallback].
+///
+type RawReply = Pin<Box<dyn Future<Output = transport::Response<Vec<u8>>> + Send>>;
 
 ///
 /// Accepts raw version of Req, Res and will wrap normal callback.
 ///
-type RawCallback = Box<dyn Fn(transport::Request<Vec<u8>>) -> transport::Response<Vec<u8>> + Sync>;
+type RawCallback = Box<dyn Fn(transport::Request<Vec<u8>>) -> RawReply + Sync>;
 
 #[derive(Default)]
 pub struct Handler(HashMap<&'static str, RawCallback>);
@@ -87,25 +282,87 @@ impl Handler {
         let _ = self.0.insert(
             method.0,
             Box::new(|req| {
-                // TODO: More robust error handling.
-                let body = bincode::deserialize::<Params>(req.body()).expect("Valid message sent");
+                let body = match bincode::deserialize::<Params>(req.body()) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        let res = req.reply_err(RpcError::new(
+                            "bad_params",
+                            format!("failed to decode params: {e}"),
+                        ));
+                        return Box::pin(std::future::ready(res)) as RawReply;
+                    }
+                };
 
                 let res = implementation.call(body);
                 let res = bincode::serialize(&res).expect("Valid serialization");
 
-                req.reply(res)
+                Box::pin(std::future::ready(req.reply(res))) as RawReply
+            }),
+        );
+
+        self
+    }
+
+    ///
+    /// Same as [Handler::add], but for implementations that need to `await`
+    /// I/O instead of running to completion synchronously on the Tokio
+    /// worker. `Server::run` spawns each invocation separately, so multiple
+    /// of these can be in flight at once on the same connection.
+    ///
+    pub fn add_async<Params, Returns, Fut, Impl>(
+        &mut self,
+        method: Method<Params, Returns>,
+        implementation: &'static Impl,
+    ) -> &mut Self
+    where
+        Params: Tuple + Serialize + DeserializeOwned,
+        Returns: Serialize + DeserializeOwned,
+        Fut: Future<Output = Returns> + Send + 'static,
+        Impl: Fn<Params, Output = Fut> + Clone + Copy + Sync,
+    {
+        let _ = self.0.insert(
+            method.0,
+            Box::new(|req| {
+                let body = match bincode::deserialize::<Params>(req.body()) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        let res = req.reply_err(RpcError::new(
+                            "bad_params",
+                            format!("failed to decode params: {e}"),
+                        ));
+                        return Box::pin(std::future::ready(res)) as RawReply;
+                    }
+                };
+
+                let fut = implementation.call(body);
+                Box::pin(async move {
+                    let res = bincode::serialize(&fut.await).expect("Valid serialization");
+                    req.reply(res)
+                }) as RawReply
             }),
         );
 
         self
     }
 
-    pub fn handle(&self, input: impl AsRef<[u8]>) -> Option<Vec<u8>> {
-        let input: transport::Request<Vec<u8>> = bincode::deserialize(input.as_ref()).ok()?;
-        self.0
-            .get(input.method())
-            .map(|call| call(input.clone()))
-            .and_then(|ref a| bincode::serialize(a).ok())
+    ///
+    /// Dispatch an already-reassembled [transport::Request] to its
+    /// registered callback, returning the future producing its reply. The
+    /// reply is scheduled at the same priority as the request, via
+    /// [transport::Request::reply]. If no callback is registered for the
+    /// request's method, the future resolves to an [RpcError] reply instead.
+    ///
+    pub fn handle(&self, input: transport::Request<Vec<u8>>) -> RawReply {
+        match self.0.get(input.method()) {
+            Some(call) => call(input),
+            None => {
+                let res = input.reply_err(RpcError::new(
+                    "unknown_method",
+                    format!("no handler registered for `{}`", input.method()),
+                ));
+                Box::pin(std::future::ready(res)) as RawReply
+            }
+        }
     }
 }
 
@@ -137,18 +394,234 @@ impl Server {
                         let handler = HANDLER.get().unwrap();
                         let mut transport = Framed::new(s, LengthDelimitedCodec::new());
 
-                        while let Some(Ok(thingy)) = transport.next().await {
-                            if let Some(res) = handler.handle(thingy) {
-                                if let Err(e) = transport.send(Bytes::from_iter(res)).await {
-                                    eprintln!("Error occurred whilst replying to request:\n\t{e}.\nTerminating client connection.");
-                                    break;
-                                }
+                        let session = match handshake::negotiate(
+                            &mut transport,
+                            OFFERED_CAPABILITIES.map(str::to_string),
+                        )
+                        .await
+                        {
+                            Ok(session) => session,
+                            Err(e) => {
+                                eprintln!("Handshake with client failed:\n\t{e}.\nDropping connection.");
+                                return;
                             }
+                        };
+                        println!("Negotiated capabilities: {:?}", session.capabilities);
+
+                        let mut layers = LayerStack::new();
+                        if session.capabilities.contains(CAP_COMPRESSION) {
+                            layers.push(Box::new(CompressionLayer::default()));
+                        }
+                        if session.capabilities.contains(CAP_ENCRYPTION) {
+                            layers.push(Box::new(EncryptionLayer::new(session.shared_secret)));
                         }
+                        let layers = Arc::new(layers);
+
+                        let format = if session.capabilities.contains(CAP_MSGPACK) {
+                            WireFormat::MessagePack
+                        } else {
+                            WireFormat::Bincode
+                        };
+
+                        let (sink, mut stream) = transport.split();
+
+                        let writer = ChunkWriter::new(sink);
+                        let mut reader = ChunkReader::new();
+
+                        let conn_id = uuid::Uuid::new_v4().to_string();
+                        let conn_session = Session {
+                            writer: writer.clone(),
+                            layers: Arc::clone(&layers),
+                            format,
+                            capabilities: session.capabilities.clone(),
+                        };
+                        session_registry()
+                            .lock()
+                            .unwrap()
+                            .insert(conn_id.clone(), conn_session.clone());
+
+                        while let Some(Ok(frame)) = stream.next().await {
+                            let Some((_priority, bytes)) = reader.feed(frame) else {
+                                continue;
+                            };
+
+                            let Ok(bytes) = layers.unwrap_incoming(bytes.into()) else {
+                                eprintln!("Dropping frame that failed to decode through the layer stack.");
+                                continue;
+                            };
+
+                            let Some(request) =
+                                transport::Request::<Vec<u8>>::from_bytes(bytes, &format)
+                            else {
+                                continue;
+                            };
+
+                            let emitter = Emitter {
+                                ref_id: request.id().to_string(),
+                                priority: request.priority(),
+                                session: conn_session.clone(),
+                            };
+
+                            // Spawned so a slow handler can't block other
+                            // in-flight requests on this connection; replies
+                            // are funnelled back through `writer`'s queue.
+                            //
+                            // `handler.handle` itself is called inside the
+                            // scoped future (rather than before it) so that
+                            // `emit`/`capabilities` are available even to a
+                            // synchronous `Handler::add` callback, whose body
+                            // runs to completion the moment `handle` is
+                            // called rather than when the returned future is
+                            // polled.
+                            let writer = writer.clone();
+                            let layers = Arc::clone(&layers);
+                            tokio::spawn(CURRENT_EMITTER.scope(emitter, async move {
+                                let res = handler.handle(request).await;
+                                let id = res.to().to_string();
+                                let priority = res.priority();
+                                let bytes = transport::Envelope::Reply(res).to_bytes(&format);
+                                let bytes = layers.wrap_outgoing(bytes.into()).to_vec();
+
+                                if let Err(e) = writer.enqueue(id, priority, bytes) {
+                                    eprintln!("Error occurred whilst replying to request:\n\t{e}.");
+                                }
+                            }));
+                        }
+
+                        session_registry().lock().unwrap().remove(&conn_id);
                     });
                 }
                 Err(e) => return Err(e.into()),
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{methodify, transport::Request, Method};
+
+    fn _add(a: usize, b: usize) -> usize {
+        a + b
+    }
+
+    #[allow(non_upper_case_globals)]
+    const add: Method<(usize, usize), usize> = methodify(&_add, "add");
+
+    #[tokio::test]
+    async fn unknown_method_replies_with_an_rpc_error() {
+        let handler = Handler::default();
+        let req = Request::<Vec<u8>>::new("missing", Vec::new());
+
+        let err = handler
+            .handle(req)
+            .await
+            .into_result()
+            .expect_err("no handler is registered for `missing`");
+        assert_eq!(err.code, "unknown_method");
+    }
+
+    #[tokio::test]
+    async fn bad_params_reply_with_an_rpc_error_instead_of_panicking() {
+        let mut handler = Handler::default();
+        handler.add(add, &_add);
+
+        // Fewer than the 16 bytes two bincode `usize`s take up.
+        let req = Request::<Vec<u8>>::new("add", b"too short".to_vec());
+
+        let err = handler
+            .handle(req)
+            .await
+            .into_result()
+            .expect_err("params don't decode as (usize, usize)");
+        assert_eq!(err.code, "bad_params");
+    }
+
+    #[tokio::test]
+    async fn well_formed_call_replies_with_the_result() {
+        let mut handler = Handler::default();
+        handler.add(add, &_add);
+
+        let body = bincode::serialize(&(2usize, 3usize)).unwrap();
+        let req = Request::<Vec<u8>>::new("add", body);
+
+        let res = handler.handle(req).await.into_result().expect("should succeed");
+        let sum: usize = bincode::deserialize(&res).unwrap();
+        assert_eq!(sum, 5);
+    }
+
+    #[tokio::test]
+    async fn add_async_awaits_the_handlers_future_before_replying() {
+        fn _double(_: usize) -> usize {
+            unimplemented!()
+        }
+        #[allow(non_upper_case_globals)]
+        const double: Method<(usize,), usize> = methodify(&_double, "double");
+
+        async fn double_async(a: usize) -> usize {
+            tokio::task::yield_now().await;
+            a * 2
+        }
+
+        let mut handler = Handler::default();
+        handler.add_async(double, &double_async);
+
+        let body = bincode::serialize(&(21usize,)).unwrap();
+        let req = Request::<Vec<u8>>::new("double", body);
+
+        let res = handler
+            .handle(req)
+            .await
+            .into_result()
+            .expect("should succeed");
+        let doubled: usize = bincode::deserialize(&res).unwrap();
+        assert_eq!(doubled, 42);
+    }
+
+    ///
+    /// [Session::push] is how [sessions] sends a push notification with no
+    /// correlation to any in-flight request &mdash; unlike [Emitter::push],
+    /// it must leave `EventFrame::ref_id` unset.
+    ///
+    #[tokio::test]
+    async fn session_push_sends_an_uncorrelated_event() {
+        use futures::StreamExt;
+        use tokio::net::UnixStream;
+
+        const ANNOUNCEMENT: crate::Event<String> = crate::eventify("announcement");
+
+        let (a, b) = UnixStream::pair().expect("paired sockets");
+        let (sink, _) = Framed::new(a, LengthDelimitedCodec::new()).split();
+        let (_, mut stream) = Framed::new(b, LengthDelimitedCodec::new()).split();
+
+        let session = Session {
+            writer: ChunkWriter::new(sink),
+            layers: Arc::new(LayerStack::new()),
+            format: WireFormat::Bincode,
+            capabilities: BTreeSet::new(),
+        };
+
+        session
+            .push(ANNOUNCEMENT, &"server restarting".to_string(), crate::transport::PRIO_NORMAL)
+            .expect("push should succeed");
+
+        let mut reader = ChunkReader::new();
+        let bytes = loop {
+            let frame = stream.next().await.unwrap().unwrap();
+            if let Some((_, bytes)) = reader.feed(frame) {
+                break bytes;
+            }
+        };
+
+        let envelope = transport::Envelope::from_bytes(bytes, &WireFormat::Bincode).unwrap();
+        let transport::Envelope::Event(frame) = envelope else {
+            panic!("expected an Event envelope");
+        };
+
+        assert_eq!(frame.ref_id(), None);
+        assert_eq!(frame.method(), "announcement");
+        let payload: String = bincode::deserialize(frame.body()).unwrap();
+        assert_eq!(payload, "server restarting");
+    }
+}
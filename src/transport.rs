@@ -0,0 +1,470 @@
+//!
+//! Utilities and middleware to help transport data.
+//!
+//! * Makes use of `serde` to represent all the data as binary, via a
+//! negotiated [format::WireFormat] (bincode by default). This only governs
+//! the *outer* [Request]/[Response]/[Envelope] frame &mdash; the inner
+//! `Params`/`Returns` body is always plain bincode (see
+//! [Request::convert_inner]/[Response::convert_inner]), since it's encoded
+//! at the [crate::Method] call site, before any connection (and the format
+//! it negotiated) exists.
+//! * Governs the structure of communication &mdash; [Request]s from the client,
+//! followed by [Response]s from the server.
+//!
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub mod format;
+pub mod handshake;
+pub mod layer;
+pub mod scheduler;
+
+pub use format::WireFormat;
+
+///
+/// Priority carried on every [Request]/[Response].
+///
+/// Lower numeric values are serviced first by the [scheduler]; a message's
+/// [Response] is always scheduled at the same priority as its [Request], so a
+/// high-priority call is never stuck behind a slow reply.
+///
+pub type RequestPriority = u8;
+
+///
+/// Time-sensitive control traffic, e.g. handshakes or cancellations.
+/// Always preempts lower priorities at the next chunk boundary.
+///
+pub const PRIO_HIGH: RequestPriority = 0x20;
+
+///
+/// Default priority for ordinary calls.
+///
+pub const PRIO_NORMAL: RequestPriority = 0x40;
+
+///
+/// Bulk transfers (file payloads, etc.) that should not starve other traffic.
+///
+pub const PRIO_BACKGROUND: RequestPriority = 0x80;
+
+///
+/// OR-able tie-break: this message is the primary one at its base level.
+///
+pub const PRIO_PRIMARY: RequestPriority = 0x00;
+
+///
+/// OR-able tie-break: this message is secondary to another at its base level.
+///
+pub const PRIO_SECONDARY: RequestPriority = 0x01;
+
+///
+/// Client-to-server message.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request<Body> {
+    ///
+    /// Unique UUID v4 for this request, to keep track of the server's response.
+    ///
+    id: String,
+
+    ///
+    /// Method's  ID.
+    ///
+    method: String,
+
+    ///
+    /// Scheduling priority, carried onto the [Response] by [Request::reply].
+    ///
+    priority: RequestPriority,
+
+    ///
+    /// Payload.
+    ///
+    body: Body,
+}
+
+impl<Body> Clone for Request<Body>
+where
+    Body: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            method: self.method.clone(),
+            priority: self.priority,
+            body: self.body.clone(),
+        }
+    }
+}
+
+impl<Body> Request<Body> {
+    pub fn new(label: impl ToString, body: Body) -> Self {
+        Self::with_priority(label, PRIO_NORMAL, body)
+    }
+
+    ///
+    /// Same as [Request::new], but scheduled at the given [RequestPriority]
+    /// instead of [PRIO_NORMAL].
+    ///
+    pub fn with_priority(label: impl ToString, priority: RequestPriority, body: Body) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            method: label.to_string(),
+            priority,
+            body,
+        }
+    }
+
+    ///
+    /// Serialize this [Request] as bytes, the outer frame using `format`
+    /// and the inner body using bincode
+    /// (guaranteed not to fail... well *nearly*...).
+    ///
+    pub fn to_bytes(self, format: &WireFormat) -> Vec<u8>
+    where
+        Body: Serialize,
+    {
+        let Self {
+            id,
+            method,
+            priority,
+            body,
+        } = self;
+        let tmp = Request {
+            id,
+            method,
+            priority,
+            body: bincode::serialize(&body).expect("Valid serialize"),
+        };
+        format.serialize(&tmp).expect("Valid serialize Round 2")
+    }
+
+    ///
+    /// Make a reply to this [Request] with the given body, scheduled at the
+    /// same [RequestPriority] as the request itself.
+    ///
+    pub fn reply<NewBody>(&self, body: NewBody) -> Response<NewBody> {
+        Response {
+            to: self.id.clone(),
+            method: self.method.clone(),
+            priority: self.priority,
+            body: Outcome::Ok(body),
+        }
+    }
+
+    ///
+    /// Same as [Request::reply], but for a handler that couldn't produce a
+    /// body at all (an unknown method, a param that failed to decode, ...).
+    ///
+    pub fn reply_err<NewBody>(&self, error: RpcError) -> Response<NewBody> {
+        Response {
+            to: self.id.clone(),
+            method: self.method.clone(),
+            priority: self.priority,
+            body: Outcome::Err(error),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+}
+
+impl Request<Vec<u8>> {
+    ///
+    /// Deserialize a raw request, with a type-erased body.
+    ///
+    /// This is done before deserializing the body seperately
+    /// (for generic erasure reasons).
+    ///
+    pub fn from_bytes(bytes: impl AsRef<[u8]>, format: &WireFormat) -> Option<Self> {
+        format.deserialize(bytes.as_ref()).ok()
+    }
+
+    ///
+    /// Deserialize this [Request]'s inner body to the desired type.
+    ///
+    /// Always bincode, regardless of the connection's negotiated
+    /// [WireFormat]: `Params`/`Returns` are encoded once, at the call site,
+    /// by [crate::Method::call_once]'s `bincode::serialize` &mdash; long
+    /// before any connection (and the [WireFormat] it negotiated) is known.
+    /// The negotiated format only ever governs how the *outer* frame is
+    /// encoded; this inner blob passes through it untouched.
+    ///
+    pub fn convert_inner<Body: DeserializeOwned>(self) -> Option<Request<Body>> {
+        let Self {
+            id,
+            method,
+            priority,
+            body,
+        } = self;
+
+        bincode::deserialize(&body)
+            .map(|body| Request {
+                id,
+                method,
+                priority,
+                body,
+            })
+            .ok()
+    }
+}
+
+impl Response<Vec<u8>> {
+    ///
+    /// Deserialize a raw [Response] into its type-erased form.
+    ///
+    pub fn from_bytes(bytes: impl AsRef<[u8]>, format: &WireFormat) -> Option<Self> {
+        format.deserialize(bytes.as_ref()).ok()
+    }
+
+    ///
+    /// Deserialize the inner type-erased body to a type, leaving an
+    /// [Outcome::Err] as-is.
+    ///
+    /// Always bincode, regardless of the connection's negotiated
+    /// [WireFormat] &mdash; see [Request::convert_inner] for why: `Returns`
+    /// is encoded with a raw `bincode::serialize` in `Handler::add`/
+    /// `Handler::add_async`, never with the negotiated format.
+    ///
+    pub fn convert_inner<Body: DeserializeOwned>(self) -> Option<Response<Body>> {
+        let Self {
+            to,
+            method,
+            priority,
+            body,
+        } = self;
+
+        let body = match body {
+            Outcome::Ok(bytes) => Outcome::Ok(bincode::deserialize(&bytes).ok()?),
+            Outcome::Err(error) => Outcome::Err(error),
+        };
+
+        Some(Response {
+            to,
+            method,
+            priority,
+            body,
+        })
+    }
+}
+
+///
+/// Structured error carried by a [Response] in place of a body, e.g. an
+/// unrecognised method or params that failed to decode. Recoverable per
+/// request &mdash; it does not bring down the connection.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: String,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: impl ToString, message: impl ToString) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+///
+/// A [Response]'s payload: either the method's return value, or the
+/// [RpcError] that kept it from being produced.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Outcome<Body> {
+    Ok(Body),
+    Err(RpcError),
+}
+
+///
+/// Server-to-client message.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response<Body> {
+    ///
+    /// Same as the associated [Request]'s id field
+    ///
+    to: String,
+
+    ///
+    /// Method's  ID.
+    ///
+    method: String,
+
+    ///
+    /// Scheduling priority, copied from the [Request] this replies to.
+    ///
+    priority: RequestPriority,
+
+    ///
+    /// Payload, or the [RpcError] that kept it from being produced.
+    ///
+    body: Outcome<Body>,
+}
+
+impl<Body> Response<Body> {
+    ///
+    /// Consumes this [Response], yielding its body or the [RpcError] it
+    /// carries instead.
+    ///
+    pub fn into_result(self) -> Result<Body, RpcError> {
+        match self.body {
+            Outcome::Ok(body) => Ok(body),
+            Outcome::Err(error) => Err(error),
+        }
+    }
+
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    ///
+    /// Serialize this [Response] as bytes, the outer frame using `format`
+    /// and the inner body using bincode.
+    ///
+    pub fn to_bytes(self, format: &WireFormat) -> Vec<u8>
+    where
+        Body: Serialize,
+    {
+        let Self {
+            to,
+            method,
+            priority,
+            body,
+        } = self;
+        let body = match body {
+            Outcome::Ok(body) => Outcome::Ok(bincode::serialize(&body).expect("Valid serialize")),
+            Outcome::Err(error) => Outcome::Err(error),
+        };
+        let tmp = Response {
+            to,
+            method,
+            priority,
+            body,
+        };
+        format.serialize(&tmp).expect("Valid serialize Round 2")
+    }
+}
+
+///
+/// Unprompted server-to-client push of an [crate::Event], as opposed to a
+/// reply to a [Request]. Optionally correlated to the request that
+/// triggered it via `ref_id` (e.g. a progress update for a long-running
+/// call), but may also be sent with no correlation at all.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventFrame {
+    ref_id: Option<String>,
+    method: String,
+    priority: RequestPriority,
+    body: Vec<u8>,
+}
+
+impl EventFrame {
+    pub fn new(
+        method: impl ToString,
+        priority: RequestPriority,
+        ref_id: Option<String>,
+        body: Vec<u8>,
+    ) -> Self {
+        Self {
+            ref_id,
+            method: method.to_string(),
+            priority,
+            body,
+        }
+    }
+
+    pub fn ref_id(&self) -> Option<&str> {
+        self.ref_id.as_deref()
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+///
+/// Every server-to-client message is wrapped in an [Envelope] so the
+/// receiver can tell a [Response] (a reply) from an [EventFrame] (an
+/// unprompted push) apart without guessing.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Envelope {
+    Reply(Response<Vec<u8>>),
+    Event(EventFrame),
+}
+
+impl Envelope {
+    pub fn from_bytes(bytes: impl AsRef<[u8]>, format: &WireFormat) -> Option<Self> {
+        format.deserialize(bytes.as_ref()).ok()
+    }
+
+    pub fn to_bytes(&self, format: &WireFormat) -> Vec<u8> {
+        format.serialize(self).expect("Valid serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Regression test for a bug where [Response::convert_inner] decoded
+    /// the inner body with the connection's negotiated [WireFormat]
+    /// instead of bincode, breaking every call once a connection
+    /// negotiated anything other than [WireFormat::Bincode].
+    ///
+    #[test]
+    fn response_inner_body_stays_bincode_through_a_non_default_outer_format() {
+        let req = Request::new("add", bincode::serialize(&(2usize, 3usize)).unwrap());
+        let res = req.reply(bincode::serialize(&5usize).unwrap());
+
+        let bytes = Envelope::Reply(res).to_bytes(&WireFormat::MessagePack);
+        let envelope = Envelope::from_bytes(bytes, &WireFormat::MessagePack)
+            .expect("outer frame round-trips through the negotiated format");
+
+        let Envelope::Reply(res) = envelope else {
+            panic!("expected a Reply envelope");
+        };
+
+        let res: Response<usize> = res
+            .convert_inner()
+            .expect("inner body is bincode regardless of the outer format");
+        assert_eq!(res.into_result().unwrap(), 5);
+    }
+}
@@ -0,0 +1,79 @@
+//!
+//! Pluggable wire formats for the outer [Request](super::Request)/
+//! [Response](super::Response)/[Envelope](super::Envelope) frames.
+//!
+
+use serde::{de::DeserializeOwned, Serialize};
+
+///
+/// A concrete wire encoding, implemented once per format below.
+///
+pub trait Format {
+    fn serialize<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>>;
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T>;
+}
+
+///
+/// The original, compact binary encoding. Still the default, for
+/// backwards compatibility with clients/servers that predate [WireFormat]
+/// negotiation.
+///
+pub struct Bincode;
+
+impl Format for Bincode {
+    fn serialize<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+///
+/// Self-describing binary encoding, useful when the two ends of the
+/// connection don't share the exact same struct definitions.
+///
+pub struct MessagePack;
+
+impl Format for MessagePack {
+    fn serialize<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+///
+/// The format negotiated for a connection, chosen during the handshake
+/// from whichever [Format]s both peers advertise support for. Carried
+/// around as a plain value (instead of `Box<dyn Format>`) since `Format`'s
+/// methods are generic and so aren't object-safe.
+///
+/// Defaults to [WireFormat::Bincode].
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn serialize<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Bincode => Bincode.serialize(value),
+            Self::MessagePack => MessagePack.serialize(value),
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        match self {
+            Self::Bincode => Bincode.deserialize(bytes),
+            Self::MessagePack => MessagePack.deserialize(bytes),
+        }
+    }
+}
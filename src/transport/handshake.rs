@@ -0,0 +1,254 @@
+//!
+//! `Hello` exchange performed immediately after a `UnixStream`/`UnixListener`
+//! accept, before any [crate::transport::Request] flows over the connection.
+//!
+//! Both peers declare the protocol version they speak and the optional
+//! capabilities they support; a major version mismatch is refused with an
+//! explicit rejection frame (rather than just dropping the connection),
+//! while capabilities are negotiated down to the intersection so later code
+//! can gate optional features (compression, events, streaming) on what the
+//! other side actually understands. An ephemeral X25519 key is exchanged in
+//! the same round trip so a negotiated [crate::transport::layer::EncryptionLayer]
+//! can be built without a second handshake.
+//!
+
+use std::collections::BTreeSet;
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::{
+    bytes::Bytes,
+    codec::{Framed, LengthDelimitedCodec},
+};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+///
+/// Bumped whenever the wire format or method set changes in a way that is
+/// not backwards-compatible. Peers refuse to talk to one another unless this
+/// matches exactly.
+///
+pub const PROTOCOL_VERSION: u32 = 1;
+
+///
+/// Capability name for [crate::transport::layer::CompressionLayer].
+///
+pub const CAP_COMPRESSION: &str = "compression";
+
+///
+/// Capability name for [crate::transport::layer::EncryptionLayer].
+///
+pub const CAP_ENCRYPTION: &str = "encryption";
+
+///
+/// Capability name for [crate::transport::format::WireFormat::MessagePack].
+/// If absent, peers fall back to [crate::transport::format::WireFormat::Bincode].
+///
+pub const CAP_MSGPACK: &str = "msgpack";
+
+///
+/// Handshake frame exchanged by both peers before any [crate::transport::Request].
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub capabilities: BTreeSet<String>,
+    pub dh_public: [u8; 32],
+}
+
+///
+/// Wire envelope for the handshake round trip: either side's [Hello], or a
+/// [HandshakeFrame::Reject] sent in its place when the peer's [Hello] can't
+/// be accepted (e.g. a [PROTOCOL_VERSION] mismatch). Sending a [Reject]
+/// instead of just dropping the connection lets the other side report
+/// *why* the handshake failed instead of seeing a bare EOF.
+///
+/// [Reject]: HandshakeFrame::Reject
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum HandshakeFrame {
+    Hello(Hello),
+    Reject { reason: String },
+}
+
+impl Hello {
+    ///
+    /// Builds a [Hello] for this side of the connection, stamped with the
+    /// current [PROTOCOL_VERSION] and carrying `dh_public`, the public half
+    /// of this side's ephemeral X25519 key.
+    ///
+    fn new(capabilities: impl IntoIterator<Item = String>, dh_public: PublicKey) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capabilities.into_iter().collect(),
+            dh_public: dh_public.to_bytes(),
+        }
+    }
+}
+
+///
+/// Outcome of a completed [negotiate] call.
+///
+pub struct NegotiatedSession {
+    ///
+    /// Intersection of both sides' declared capabilities.
+    ///
+    pub capabilities: BTreeSet<String>,
+
+    ///
+    /// Key material derived from the ephemeral X25519 exchange, suitable for
+    /// seeding a [crate::transport::layer::EncryptionLayer] when both sides
+    /// negotiated [CAP_ENCRYPTION].
+    ///
+    pub shared_secret: [u8; 32],
+}
+
+///
+/// Exchanges a [Hello] (built from `capabilities`) with the peer's over
+/// `transport`, refusing the connection if the peer's [PROTOCOL_VERSION] does
+/// not match ours.
+///
+pub async fn negotiate<T>(
+    transport: &mut Framed<T, LengthDelimitedCodec>,
+    capabilities: impl IntoIterator<Item = String>,
+) -> anyhow::Result<NegotiatedSession>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let public = PublicKey::from(&secret);
+
+    // The handshake itself is always bincode: the [WireFormat] it negotiates
+    // isn't known yet, so it can't encode itself with it.
+    let local = Hello::new(capabilities, public);
+    let bytes =
+        bincode::serialize(&HandshakeFrame::Hello(local.clone())).expect("Valid serialize");
+    transport.send(Bytes::from(bytes)).await?;
+
+    let frame = transport
+        .next()
+        .await
+        .ok_or_else(|| anyhow::format_err!("connection closed during handshake"))??;
+
+    let remote = match bincode::deserialize(&frame) {
+        Ok(HandshakeFrame::Hello(hello)) => hello,
+        Ok(HandshakeFrame::Reject { reason }) => {
+            anyhow::bail!("peer refused the handshake: {reason}")
+        }
+        Err(_) => anyhow::bail!("peer sent a malformed Hello frame"),
+    };
+
+    if remote.protocol_version != PROTOCOL_VERSION {
+        let reason = format!(
+            "protocol version mismatch: we speak v{PROTOCOL_VERSION}, peer speaks v{}",
+            remote.protocol_version
+        );
+
+        // Tell the peer why before giving up, so they see a clear refusal
+        // instead of the connection just dropping.
+        let reject = bincode::serialize(&HandshakeFrame::Reject {
+            reason: reason.clone(),
+        })
+        .expect("Valid serialize");
+        let _ = transport.send(Bytes::from(reject)).await;
+
+        anyhow::bail!(reason);
+    }
+
+    let shared = secret.diffie_hellman(&PublicKey::from(remote.dh_public));
+    let shared_secret: [u8; 32] = sha2::Sha256::digest(shared.as_bytes()).into();
+
+    Ok(NegotiatedSession {
+        capabilities: local
+            .capabilities
+            .intersection(&remote.capabilities)
+            .cloned()
+            .collect(),
+        shared_secret,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn negotiates_successfully_and_intersects_capabilities_down_to_the_common_subset() {
+        let (a, b) = UnixStream::pair().expect("paired sockets");
+        let mut framed_a = Framed::new(a, LengthDelimitedCodec::new());
+        let mut framed_b = Framed::new(b, LengthDelimitedCodec::new());
+
+        let (res_a, res_b) = tokio::join!(
+            negotiate(
+                &mut framed_a,
+                [CAP_COMPRESSION, CAP_MSGPACK].map(str::to_string)
+            ),
+            negotiate(
+                &mut framed_b,
+                [CAP_COMPRESSION, CAP_ENCRYPTION].map(str::to_string)
+            ),
+        );
+
+        let session_a = res_a.expect("negotiation should succeed");
+        let session_b = res_b.expect("negotiation should succeed");
+
+        let expected: BTreeSet<String> = [CAP_COMPRESSION.to_string()].into_iter().collect();
+        assert_eq!(session_a.capabilities, expected);
+        assert_eq!(session_b.capabilities, expected);
+
+        // Both sides derive the same key from the X25519 exchange.
+        assert_eq!(session_a.shared_secret, session_b.shared_secret);
+    }
+
+    #[tokio::test]
+    async fn version_mismatch_is_refused_locally_and_reported_to_the_peer() {
+        let (a, b) = UnixStream::pair().expect("paired sockets");
+        let mut framed_a = Framed::new(a, LengthDelimitedCodec::new());
+        let mut framed_b = Framed::new(b, LengthDelimitedCodec::new());
+
+        let bad_hello = HandshakeFrame::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: BTreeSet::new(),
+            dh_public: [0u8; 32],
+        });
+        framed_b
+            .send(Bytes::from(bincode::serialize(&bad_hello).unwrap()))
+            .await
+            .unwrap();
+
+        let err = negotiate(&mut framed_a, Vec::<String>::new())
+            .await
+            .expect_err("a version mismatch should be refused");
+        assert!(err.to_string().contains("protocol version mismatch"));
+
+        // `a`'s own Hello, sent before it read ours off the wire.
+        let _ = framed_b.next().await.unwrap().unwrap();
+
+        // The explicit Reject it sent afterwards instead of just dropping
+        // the connection.
+        let reject = framed_b.next().await.unwrap().unwrap();
+        let frame: HandshakeFrame = bincode::deserialize(&reject).unwrap();
+        assert!(matches!(frame, HandshakeFrame::Reject { .. }));
+    }
+
+    #[tokio::test]
+    async fn a_reject_frame_from_the_peer_surfaces_its_reason() {
+        let (a, b) = UnixStream::pair().expect("paired sockets");
+        let mut framed_a = Framed::new(a, LengthDelimitedCodec::new());
+        let mut framed_b = Framed::new(b, LengthDelimitedCodec::new());
+
+        let reject = HandshakeFrame::Reject {
+            reason: "computer says no".to_string(),
+        };
+        framed_b
+            .send(Bytes::from(bincode::serialize(&reject).unwrap()))
+            .await
+            .unwrap();
+
+        let err = negotiate(&mut framed_a, Vec::<String>::new())
+            .await
+            .expect_err("a Reject from the peer should fail negotiation");
+        assert!(err.to_string().contains("computer says no"));
+    }
+}
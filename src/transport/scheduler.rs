@@ -0,0 +1,283 @@
+//!
+//! Splits large, already-framed messages into fixed-size [Chunk]s and
+//! schedules them so that a single big payload (e.g. a file transfer) cannot
+//! monopolize the socket and starve small, high-priority control calls.
+//!
+//! Messages of equal priority are round-robined one chunk at a time; the
+//! writer always re-checks for the numerically-lowest priority present
+//! before sending the next chunk, so a higher-priority message preempts a
+//! lower one at the next chunk boundary.
+//!
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use futures::{Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::bytes::Bytes;
+
+use super::RequestPriority;
+
+///
+/// Chunks are capped at this many bytes of payload each.
+///
+pub const CHUNK_SIZE: usize = 0x4000;
+
+///
+/// One fragment of a chunked message, tagged with the id of the message it
+/// belongs to and whether it is the last fragment.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    pub id: String,
+    pub priority: RequestPriority,
+    pub seq: u32,
+    pub final_chunk: bool,
+    pub data: Vec<u8>,
+}
+
+///
+/// A message queued for sending, sliced into [CHUNK_SIZE] chunks as it is
+/// drained by the writer loop.
+///
+struct PendingMessage {
+    id: String,
+    bytes: Vec<u8>,
+    offset: usize,
+    seq: u32,
+}
+
+impl PendingMessage {
+    fn new(id: String, bytes: Vec<u8>) -> Self {
+        Self {
+            id,
+            bytes,
+            offset: 0,
+            seq: 0,
+        }
+    }
+
+    fn next_chunk(&mut self, priority: RequestPriority) -> Chunk {
+        let end = (self.offset + CHUNK_SIZE).min(self.bytes.len());
+        let data = self.bytes[self.offset..end].to_vec();
+        self.offset = end;
+
+        let chunk = Chunk {
+            id: self.id.clone(),
+            priority,
+            seq: self.seq,
+            final_chunk: self.offset >= self.bytes.len(),
+            data,
+        };
+
+        self.seq += 1;
+        chunk
+    }
+}
+
+///
+/// Sits in front of a framed byte sink and round-robins chunks of queued
+/// messages, lowest-priority-value first. Cheaply cloneable: clones share the
+/// same underlying writer task via [ChunkWriter::enqueue]'s channel.
+///
+#[derive(Clone)]
+pub struct ChunkWriter {
+    tx: mpsc::UnboundedSender<(RequestPriority, String, Vec<u8>)>,
+}
+
+impl ChunkWriter {
+    ///
+    /// Spawns the background writer task driving `sink`. Each call to
+    /// [ChunkWriter::enqueue] hands it one whole (unchunked) message.
+    ///
+    pub fn new<T>(sink: T) -> Self
+    where
+        T: Sink<Bytes, Error = std::io::Error> + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::run(sink, rx));
+        Self { tx }
+    }
+
+    ///
+    /// Queue `bytes` (a whole, already-serialized message) for sending under
+    /// `id`, at the given `priority`.
+    ///
+    pub fn enqueue(
+        &self,
+        id: String,
+        priority: RequestPriority,
+        bytes: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send((priority, id, bytes))
+            .map_err(|_| anyhow::format_err!("chunk writer task is no longer running"))
+    }
+
+    async fn run<T>(
+        mut sink: T,
+        mut rx: mpsc::UnboundedReceiver<(RequestPriority, String, Vec<u8>)>,
+    ) where
+        T: Sink<Bytes, Error = std::io::Error> + Unpin,
+    {
+        let mut queues: BTreeMap<RequestPriority, VecDeque<PendingMessage>> = BTreeMap::new();
+
+        loop {
+            while let Ok((priority, id, bytes)) = rx.try_recv() {
+                queues
+                    .entry(priority)
+                    .or_default()
+                    .push_back(PendingMessage::new(id, bytes));
+            }
+
+            let Some(priority) = queues
+                .iter()
+                .find(|(_, q)| !q.is_empty())
+                .map(|(p, _)| *p)
+            else {
+                match rx.recv().await {
+                    Some((priority, id, bytes)) => {
+                        queues
+                            .entry(priority)
+                            .or_default()
+                            .push_back(PendingMessage::new(id, bytes));
+                        continue;
+                    }
+                    None => return,
+                }
+            };
+
+            let queue = queues.get_mut(&priority).expect("just found non-empty");
+            let mut message = queue.pop_front().expect("queue was non-empty");
+            let chunk = message.next_chunk(priority);
+            let finished = chunk.final_chunk;
+
+            let bytes = bincode::serialize(&chunk).expect("Valid serialize");
+            if sink.send(Bytes::from(bytes)).await.is_err() {
+                return;
+            }
+
+            if !finished {
+                queue.push_back(message);
+            }
+        }
+    }
+}
+
+///
+/// Reassembles incoming [Chunk]s back into whole messages, keyed by id.
+///
+#[derive(Default)]
+pub struct ChunkReader {
+    partial: HashMap<String, (RequestPriority, Vec<u8>)>,
+}
+
+impl ChunkReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Feed in one framed [Chunk]. Returns the reassembled message (along
+    /// with the priority it was sent at) once its final chunk has arrived.
+    ///
+    pub fn feed(&mut self, bytes: impl AsRef<[u8]>) -> Option<(RequestPriority, Vec<u8>)> {
+        let chunk: Chunk = bincode::deserialize(bytes.as_ref()).ok()?;
+
+        let (_, buf) = self
+            .partial
+            .entry(chunk.id.clone())
+            .or_insert_with(|| (chunk.priority, Vec::new()));
+        buf.extend_from_slice(&chunk.data);
+
+        if chunk.final_chunk {
+            self.partial.remove(&chunk.id)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+
+    use crate::transport::{PRIO_BACKGROUND, PRIO_HIGH};
+
+    #[test]
+    fn reassembles_a_message_split_across_several_chunks() {
+        let mut message = PendingMessage::new("msg".to_string(), vec![7u8; CHUNK_SIZE * 2 + 10]);
+        let mut reader = ChunkReader::new();
+
+        let bytes = loop {
+            let chunk = message.next_chunk(PRIO_HIGH);
+            let finished = chunk.final_chunk;
+            let raw = bincode::serialize(&chunk).unwrap();
+
+            match reader.feed(raw) {
+                Some((priority, bytes)) => {
+                    assert!(finished, "feed should only return once on the final chunk");
+                    assert_eq!(priority, PRIO_HIGH);
+                    break bytes;
+                }
+                None => assert!(!finished),
+            }
+        };
+
+        assert_eq!(bytes, vec![7u8; CHUNK_SIZE * 2 + 10]);
+    }
+
+    ///
+    /// A sink backed by a bounded `futures` channel, so the test can hold a
+    /// chunk back (by not calling `.next()` on the receiver) to force the
+    /// writer task to suspend mid-send, at a controlled point.
+    ///
+    fn bounded_sink() -> (
+        impl Sink<Bytes, Error = std::io::Error> + Unpin + Send + 'static,
+        mpsc::Receiver<Bytes>,
+    ) {
+        let (tx, rx) = mpsc::channel::<Bytes>(0);
+        (
+            tx.sink_map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed")),
+            rx,
+        )
+    }
+
+    #[tokio::test]
+    async fn higher_priority_messages_preempt_a_lower_one_already_in_progress() {
+        use futures::StreamExt as _;
+
+        let (sink, mut rx) = bounded_sink();
+        let writer = ChunkWriter::new(sink);
+
+        writer
+            .enqueue("bulk".to_string(), PRIO_BACKGROUND, vec![1u8; CHUNK_SIZE * 3])
+            .unwrap();
+
+        // Pull the bulk message's first chunk off the wire. The bounded
+        // sink's send only completes once the receiver has taken the chunk,
+        // so when this resolves the writer task is still finishing that
+        // first send and has not picked its next chunk yet; a high-priority
+        // message enqueued now is the very next chunk sent.
+        let first: Chunk = bincode::deserialize(&rx.next().await.unwrap()).unwrap();
+        assert_eq!((first.id.as_str(), first.seq), ("bulk", 0));
+
+        writer
+            .enqueue("urgent".to_string(), PRIO_HIGH, vec![9u8; 4])
+            .unwrap();
+
+        let second: Chunk = bincode::deserialize(&rx.next().await.unwrap()).unwrap();
+        assert_eq!(
+            second.id, "urgent",
+            "the high-priority message should preempt the rest of \"bulk\" at the next chunk boundary"
+        );
+        assert!(second.final_chunk);
+
+        let third: Chunk = bincode::deserialize(&rx.next().await.unwrap()).unwrap();
+        assert_eq!((third.id.as_str(), third.seq), ("bulk", 1));
+
+        let fourth: Chunk = bincode::deserialize(&rx.next().await.unwrap()).unwrap();
+        assert_eq!((fourth.id.as_str(), fourth.seq), ("bulk", 2));
+    }
+}